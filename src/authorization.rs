@@ -1,21 +1,94 @@
 use select::document::Document;
 use select::predicate::Attr;
 use std::collections::HashMap;
-use reqwest::{Client, RedirectPolicy, RequestBuilder, Response};
+use std::fs::File;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use reqwest::{Client, RequestBuilder, Response};
+use reqwest::redirect::Policy;
 use reqwest::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE};
 use url::Url;
 use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use thiserror::Error;
 
 const AUTH_BASE_URL: &'static str = "https://luminus.nus.edu.sg";
 const DISCOVERY_PATH: &'static str = "/v2/auth/.well-known/openid-configuration";
 const CLIENT_ID: &'static str = "verso";
 const SCOPE: &'static str = "profile email role openid lms.read calendar.read lms.delete lms.write calendar.write gradebook.write offline_access";
-const RESPONSE_TYPE: &'static str = "id_token token code";
+const RESPONSE_TYPE: &'static str = "code";
 const REDIRECT_URI: &'static str = "https://luminus.nus.edu.sg/auth/callback";
+const CODE_VERIFIER_LENGTH: usize = 64;
+const CODE_VERIFIER_CHARSET: &'static [u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-._~";
+
+/// Errors produced by the login, renewal, and session-persistence flows.
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("network request failed: {0}")]
+    Network(#[from] reqwest::Error),
+    #[error("invalid credentials")]
+    InvalidCredentials,
+    #[error("please login first")]
+    NotLoggedIn,
+    #[error("missing redirect in server response")]
+    MissingRedirect,
+    #[error("unable to decode OIDC discovery/JWKS response: {0}")]
+    Discovery(#[source] reqwest::Error),
+    #[error("unable to decode id_token: {0}")]
+    TokenDecode(#[source] jsonwebtoken::errors::Error),
+    #[error("id_token failed validation: {0}")]
+    TokenValidation(String),
+    #[error("unable to decode token endpoint response: {0}")]
+    TokenResponseDecode(#[source] reqwest::Error),
+    #[error("unable to parse callback: {0}")]
+    CallbackParse(String),
+    #[error("invalid HTTP header: {0}")]
+    Header(String),
+    #[error("unable to parse url: {0}")]
+    UrlParse(#[from] url::ParseError),
+    #[error("unable to persist session: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("unable to (de)serialize session: {0}")]
+    Session(#[from] serde_json::Error),
+}
 
 #[derive(Deserialize)]
 struct Discovery {
+    issuer: String,
     authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kid: String,
+    n: String,
+    e: String,
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    nonce: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ExpClaim {
+    exp: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionData {
+    cookies: HashMap<String, String>,
+    jwt: Option<String>,
+    refresh_token: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -44,126 +117,342 @@ struct LoginInfo {
 pub struct Authorization {
     pub jwt: Option<String>,
     cookies: HashMap<String, String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+    state: Option<String>,
+    nonce: Option<String>,
 }
 
-fn full_auth_url(path: &str) -> Url {
-    Url::parse(AUTH_BASE_URL).and_then(|u| u.join(path)).expect("Unable to join URL's")
+fn full_auth_url(path: &str) -> Result<Url, AuthError> {
+    Ok(Url::parse(AUTH_BASE_URL)?.join(path)?)
 }
 
-pub fn auth_endpoint_uri() -> Url {
-    let discovery_url = full_auth_url(DISCOVERY_PATH);
-    let discovery: Discovery = reqwest::get(discovery_url).expect("Failed to HTTP GET the discovery path").json().expect("Unable to deserialize discovery json");
-    let mut auth_url = Url::parse(&discovery.authorization_endpoint).expect("Unable to parse discovery url");
-    add_auth_params(&mut auth_url);
-    auth_url
+async fn discover() -> Result<Discovery, AuthError> {
+    let discovery_url = full_auth_url(DISCOVERY_PATH)?;
+    let response = reqwest::get(discovery_url).await?;
+    response.json().await.map_err(AuthError::Discovery)
 }
 
-fn add_auth_params(auth_url: &mut Url) {
-    auth_url.query_pairs_mut()
-        .append_pair("state", &generate_random_bytes(16))
-        .append_pair("nonce", &generate_random_bytes(16))
-        .append_pair("client_id", CLIENT_ID)
-        .append_pair("scope", SCOPE)
-        .append_pair("response_type", RESPONSE_TYPE)
-        .append_pair("redirect_uri", REDIRECT_URI);
+async fn fetch_jwks(jwks_uri: &str) -> Result<Jwks, AuthError> {
+    let url = Url::parse(jwks_uri)?;
+    let response = reqwest::get(url).await?;
+    response.json().await.map_err(AuthError::Discovery)
 }
 
-fn build_client() -> Result<Client, &'static str> {
-    Client::builder().redirect(RedirectPolicy::none()).build().map_err(|_|"Unable to create HTTP client")
+async fn validate_id_token(id_token: &str, discovery: &Discovery, nonce: Option<&str>) -> Result<(), AuthError> {
+    let header = jsonwebtoken::decode_header(id_token).map_err(AuthError::TokenDecode)?;
+    let kid = header.kid.ok_or_else(|| AuthError::TokenValidation("missing key id in id_token header".to_string()))?;
+    let jwks = fetch_jwks(&discovery.jwks_uri).await?;
+    let jwk = jwks.keys.iter().find(|k| k.kid == kid).ok_or_else(|| AuthError::TokenValidation("unknown id_token signing key".to_string()))?;
+    let decoding_key = DecodingKey::from_rsa_components(&jwk.n, &jwk.e);
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.iss = Some(discovery.issuer.clone());
+    validation.set_audience(&[CLIENT_ID]);
+    let token_data = jsonwebtoken::decode::<IdTokenClaims>(id_token, &decoding_key, &validation).map_err(AuthError::TokenDecode)?;
+    // The refresh_token grant doesn't carry the original authorization request's nonce.
+    if let Some(expected_nonce) = nonce {
+        if token_data.claims.nonce.as_deref() != Some(expected_nonce) {
+            return Err(AuthError::TokenValidation("id_token nonce does not match the request nonce".to_string()));
+        }
+    }
+    Ok(())
+}
+
+fn build_client() -> Result<Client, AuthError> {
+    Ok(Client::builder().redirect(Policy::none()).build()?)
 }
 
 pub fn generate_random_bytes(size: usize) -> String {
     (0..size).map(|_| format!("{:02x}", rand::random::<u8>())).collect()
 }
 
-fn get_redirect_url(response: Response) -> Result<Url, &'static str> {
-    let location = response.headers().get(LOCATION).ok_or("Invalid response from server, expected redirection")?
-        .to_str().map_err(|_| "Unable to read location header")?.to_string();
-    let url = Url::parse(&location).map_err(|_| " Unable to parse the url of location")?;
+fn generate_code_verifier() -> String {
+    (0..CODE_VERIFIER_LENGTH)
+        .map(|_| CODE_VERIFIER_CHARSET[rand::random::<usize>() % CODE_VERIFIER_CHARSET.len()] as char)
+        .collect()
+}
+
+fn generate_code_challenge(code_verifier: &str) -> String {
+    let digest = Sha256::digest(code_verifier.as_bytes());
+    base64::encode_config(&digest, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_exp(jwt: &str) -> Result<i64, AuthError> {
+    let payload = jwt.split('.').nth(1).ok_or_else(|| AuthError::TokenValidation("invalid JWT".to_string()))?;
+    let decoded = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).map_err(|e| AuthError::TokenValidation(e.to_string()))?;
+    let claims: ExpClaim = serde_json::from_slice(&decoded).map_err(|e| AuthError::TokenValidation(e.to_string()))?;
+    Ok(claims.exp)
+}
+
+fn get_redirect_url(response: Response) -> Result<Url, AuthError> {
+    let location = response.headers().get(LOCATION).ok_or(AuthError::MissingRedirect)?
+        .to_str().map_err(|e| AuthError::Header(e.to_string()))?.to_string();
+    let url = Url::parse(&location)?;
     Ok(url)
 }
 
 impl Authorization {
     pub fn new() -> Authorization {
-        Authorization { jwt: None, cookies: HashMap::new() }
+        Authorization { jwt: None, cookies: HashMap::new(), code_verifier: None, refresh_token: None, state: None, nonce: None }
+    }
+
+    pub fn save_session(&self, path: &Path) -> Result<(), AuthError> {
+        let session = SessionData {
+            cookies: self.cookies.clone(),
+            jwt: self.jwt.clone(),
+            refresh_token: self.refresh_token.clone(),
+        };
+        let file = File::create(path)?;
+        serde_json::to_writer(file, &session)?;
+        Ok(())
+    }
+
+    pub fn load_session(path: &Path) -> Result<Authorization, AuthError> {
+        let file = File::open(path)?;
+        let session: SessionData = serde_json::from_reader(file)?;
+        Ok(Authorization {
+            jwt: session.jwt,
+            cookies: session.cookies,
+            refresh_token: session.refresh_token,
+            code_verifier: None,
+            state: None,
+            nonce: None,
+        })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let jwt = match &self.jwt {
+            Some(jwt) => jwt,
+            None => return true,
+        };
+        let exp = match decode_exp(jwt) {
+            Ok(exp) => exp,
+            Err(_) => return true,
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+        now >= exp
+    }
+
+    pub async fn auth_endpoint_uri(&mut self) -> Result<Url, AuthError> {
+        let discovery = discover().await?;
+        let mut auth_url = Url::parse(&discovery.authorization_endpoint)?;
+        self.add_auth_params(&mut auth_url);
+        Ok(auth_url)
     }
 
-    fn http_post<T: Serialize + ?Sized>(&mut self, url: Url, query: &T) -> Result<Response, &'static str> {
+    fn add_auth_params(&mut self, auth_url: &mut Url) {
+        let code_verifier = generate_code_verifier();
+        let code_challenge = generate_code_challenge(&code_verifier);
+        let state = generate_random_bytes(16);
+        let nonce = generate_random_bytes(16);
+        self.code_verifier = Some(code_verifier);
+        self.state = Some(state.clone());
+        self.nonce = Some(nonce.clone());
+        auth_url.query_pairs_mut()
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("client_id", CLIENT_ID)
+            .append_pair("scope", SCOPE)
+            .append_pair("response_type", RESPONSE_TYPE)
+            .append_pair("redirect_uri", REDIRECT_URI)
+            .append_pair("code_challenge", &code_challenge)
+            .append_pair("code_challenge_method", "S256");
+    }
+
+    async fn http_post<T: Serialize + ?Sized>(&mut self, url: Url, query: &T) -> Result<Response, AuthError> {
         let client = build_client()?;
-        let response = self.add_cookie_header(client.post(url)).form(query).send().map_err(|_|"Failed HTTP request")?;
+        let response = self.add_cookie_header(client.post(url))?.form(query).send().await?;
         for c in response.headers().get_all(SET_COOKIE).iter() {
-            let cookie = c.to_str().map_err(|_| "Unable to read set-cookie header")?.to_string();
-            self.add_cookie(cookie);
+            let cookie = c.to_str().map_err(|e| AuthError::Header(e.to_string()))?.to_string();
+            self.add_cookie(cookie)?;
         }
         Ok(response)
     }
 
-    fn http_get(&mut self, url: Url) -> Result<Response, &'static str> {
+    async fn http_get(&mut self, url: Url) -> Result<Response, AuthError> {
         let client = build_client()?;
-        let response = self.add_cookie_header(client.get(url)).send().map_err(|_| "Failed HTTP request")?;
+        let response = self.add_cookie_header(client.get(url))?.send().await?;
         for c in response.headers().get_all(SET_COOKIE).iter() {
-            let cookie = c.to_str().map_err(|_| "Unable to read set-cookie header")?.to_string();
-            self.add_cookie(cookie);
+            let cookie = c.to_str().map_err(|e| AuthError::Header(e.to_string()))?.to_string();
+            self.add_cookie(cookie)?;
         }
         Ok(response)
     }
 
-    pub fn login(&mut self, username: &str, password: &str) -> Result<bool, &'static str> {
-        let login_info = self.auth_login_info()?;
-        let url = full_auth_url(&login_info.login_url);
+    pub async fn login(&mut self, username: &str, password: &str) -> Result<bool, AuthError> {
+        let login_info = self.auth_login_info().await?;
+        let url = full_auth_url(&login_info.login_url)?;
         let params = login_info.anti_forgery.build_login_params(username, password);
-        let first_response = self.http_post(url, &params)?;
+        let first_response = self.http_post(url, &params).await?;
         if !first_response.status().is_redirection() {
-            return Err("Invalid credentials");
+            return Err(AuthError::InvalidCredentials);
         }
         let second_url = get_redirect_url(first_response)?;
-        let callback_url = get_redirect_url(self.http_get(second_url)?)?;
-        return self.handle_callback(callback_url);
+        let callback_url = get_redirect_url(self.http_get(second_url).await?)?;
+        return self.handle_callback(callback_url).await;
     }
 
-    pub fn renew(&mut self) -> Result<bool, &'static str> {
+    pub async fn renew(&mut self) -> Result<bool, AuthError> {
         if self.jwt.is_none() {
-            return Err("Please login first.")
+            return Err(AuthError::NotLoggedIn);
+        }
+        if self.refresh_token.is_some() {
+            return self.renew_with_refresh_token().await;
         }
-        let auth_url = auth_endpoint_uri();
-        let callback_url = get_redirect_url(self.http_get(auth_url)?)?;
+        let auth_url = self.auth_endpoint_uri().await?;
+        let callback_url = get_redirect_url(self.http_get(auth_url).await?)?;
         println!("{}", &callback_url);
-        return self.handle_callback(callback_url);
+        return self.handle_callback(callback_url).await;
     }
 
-    fn handle_callback(&mut self, callback_url: Url) -> Result<bool, &'static str> {
-        let fragment = callback_url.fragment().ok_or("Invalid callback")?;
-        let response: HashMap<String, String> = serde_urlencoded::from_str(&fragment).map_err(|_| "Invalid callback")?;
-        self.jwt = Some(response["id_token"].to_owned());
-        let idsrv = self.cookies["idsrv"].to_owned();
+    async fn renew_with_refresh_token(&mut self) -> Result<bool, AuthError> {
+        let refresh_token = self.refresh_token.clone().ok_or(AuthError::NotLoggedIn)?;
+        let discovery = discover().await?;
+        let token_url = Url::parse(&discovery.token_endpoint)?;
+        let mut params = HashMap::new();
+        params.insert("grant_type", "refresh_token");
+        params.insert("refresh_token", refresh_token.as_str());
+        params.insert("client_id", CLIENT_ID);
+        params.insert("scope", SCOPE);
+        let response = self.http_post(token_url, &params).await?;
+        let token_response: HashMap<String, String> = response.json().await.map_err(AuthError::TokenResponseDecode)?;
+        let id_token = token_response.get("id_token").ok_or_else(|| AuthError::CallbackParse("missing id_token".to_string()))?.to_owned();
+        validate_id_token(&id_token, &discovery, None).await?;
+        self.jwt = Some(id_token);
+        if let Some(new_refresh_token) = token_response.get("refresh_token") {
+            self.refresh_token = Some(new_refresh_token.to_owned());
+        }
+        Ok(true)
+    }
+
+    async fn handle_callback(&mut self, callback_url: Url) -> Result<bool, AuthError> {
+        let query: HashMap<String, String> = callback_url.query_pairs().into_owned().collect();
+        let code = query.get("code").ok_or_else(|| AuthError::CallbackParse("missing code".to_string()))?.to_owned();
+        let state = query.get("state").ok_or_else(|| AuthError::CallbackParse("missing state".to_string()))?.to_owned();
+        if self.state.take() != Some(state) {
+            return Err(AuthError::CallbackParse("callback state does not match the request state".to_string()));
+        }
+        let code_verifier = self.code_verifier.take().ok_or_else(|| AuthError::CallbackParse("missing code verifier".to_string()))?;
+        let nonce = self.nonce.take().ok_or_else(|| AuthError::CallbackParse("missing nonce".to_string()))?;
+        let discovery = discover().await?;
+        let token_url = Url::parse(&discovery.token_endpoint)?;
+        let mut params = HashMap::new();
+        params.insert("grant_type", "authorization_code");
+        params.insert("code", &code);
+        params.insert("redirect_uri", REDIRECT_URI);
+        params.insert("client_id", CLIENT_ID);
+        params.insert("code_verifier", &code_verifier);
+        let response = self.http_post(token_url, &params).await?;
+        let token_response: HashMap<String, String> = response.json().await.map_err(AuthError::TokenResponseDecode)?;
+        let id_token = token_response.get("id_token").ok_or_else(|| AuthError::CallbackParse("missing id_token".to_string()))?.to_owned();
+        validate_id_token(&id_token, &discovery, Some(&nonce)).await?;
+        self.jwt = Some(id_token);
+        self.refresh_token = token_response.get("refresh_token").map(|t| t.to_owned());
+        let idsrv = self.cookies.get("idsrv").ok_or_else(|| AuthError::CallbackParse("missing idsrv cookie".to_string()))?.to_owned();
         self.cookies = HashMap::new();
         self.cookies.insert("idsrv".to_string(), idsrv);
         Ok(true)
     }
 
 
-    fn auth_login_info(&mut self) -> Result<LoginInfo, &'static str> {
-        let auth_url = auth_endpoint_uri();
-        let second_url = get_redirect_url(self.http_get(auth_url)?)?;
-        let second_body = self.http_get(second_url)?.text().map_err(|_| "Unable to read HTTP response body")?;
-        let raw_json = Document::from(second_body.as_str()).find(Attr("id", "modelJson")).last().ok_or("No JSON was sent")?.text().trim().to_owned();
-        let json = htmlescape::decode_html(&raw_json).map_err(|_| "Unable to decode HTML entities")?;
-        let login_info: LoginInfo = serde_json::from_str(&json).map_err(|_| "Unable to decode JSON")?;
+    async fn auth_login_info(&mut self) -> Result<LoginInfo, AuthError> {
+        let auth_url = self.auth_endpoint_uri().await?;
+        let second_url = get_redirect_url(self.http_get(auth_url).await?)?;
+        let second_body = self.http_get(second_url).await?.text().await?;
+        let raw_json = Document::from(second_body.as_str()).find(Attr("id", "modelJson")).last().ok_or_else(|| AuthError::CallbackParse("no JSON was sent".to_string()))?.text().trim().to_owned();
+        let json = htmlescape::decode_html(&raw_json).map_err(|_| AuthError::CallbackParse("unable to decode HTML entities".to_string()))?;
+        let login_info: LoginInfo = serde_json::from_str(&json).map_err(|e| AuthError::CallbackParse(e.to_string()))?;
         Ok(login_info)
     }
 
-    fn add_cookie(&mut self, set_cookie_header: String) {
-        let c = cookie::Cookie::parse(set_cookie_header).expect("Unable to parse cookie");
+    fn add_cookie(&mut self, set_cookie_header: String) -> Result<(), AuthError> {
+        let c = cookie::Cookie::parse(set_cookie_header).map_err(|e| AuthError::Header(e.to_string()))?;
         let (name, value) = c.name_value();
         self.cookies.insert(name.to_owned(), value.to_owned());
+        Ok(())
     }
 
     fn generate_cookie_header(&self) -> String {
         self.cookies.iter().map(|(k, v)| format!("{}={}; ", k, v)).collect()
     }
 
-    fn add_cookie_header(&mut self, request_builder: RequestBuilder) -> RequestBuilder {
-        let cookie_value = HeaderValue::from_str(&self.generate_cookie_header()).expect("Unable to add cookie header");
-        request_builder.header(COOKIE, cookie_value)
+    fn add_cookie_header(&mut self, request_builder: RequestBuilder) -> Result<RequestBuilder, AuthError> {
+        let cookie_value = HeaderValue::from_str(&self.generate_cookie_header()).map_err(|e| AuthError::Header(e.to_string()))?;
+        Ok(request_builder.header(COOKIE, cookie_value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_verifier_is_the_right_length_and_charset() {
+        let verifier = generate_code_verifier();
+        assert_eq!(verifier.len(), CODE_VERIFIER_LENGTH);
+        assert!(verifier.bytes().all(|b| CODE_VERIFIER_CHARSET.contains(&b)));
+    }
+
+    #[test]
+    fn code_verifier_is_randomized() {
+        assert_ne!(generate_code_verifier(), generate_code_verifier());
+    }
+
+    #[test]
+    fn code_challenge_matches_rfc7636_test_vector() {
+        // Test vector from RFC 7636 Appendix B.
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let challenge = generate_code_challenge(verifier);
+        assert_eq!(challenge, "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+    }
+
+    fn fake_jwt_with_exp(exp: i64) -> String {
+        let header = base64::encode_config("{}", base64::URL_SAFE_NO_PAD);
+        let payload = base64::encode_config(format!("{{\"exp\":{}}}", exp), base64::URL_SAFE_NO_PAD);
+        format!("{}.{}.signature", header, payload)
+    }
+
+    #[test]
+    fn decode_exp_reads_the_exp_claim() {
+        let jwt = fake_jwt_with_exp(1234567890);
+        assert_eq!(decode_exp(&jwt).unwrap(), 1234567890);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn is_expired_is_true_without_a_jwt() {
+        let auth = Authorization::new();
+        assert!(auth.is_expired());
+    }
+
+    #[test]
+    fn is_expired_reflects_the_exp_claim() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+
+        let mut expired = Authorization::new();
+        expired.jwt = Some(fake_jwt_with_exp(now - 60));
+        assert!(expired.is_expired());
+
+        let mut valid = Authorization::new();
+        valid.jwt = Some(fake_jwt_with_exp(now + 3600));
+        assert!(!valid.is_expired());
+    }
+
+    #[test]
+    fn session_round_trips_through_disk() {
+        let mut auth = Authorization::new();
+        auth.jwt = Some(fake_jwt_with_exp(9999999999));
+        auth.refresh_token = Some("a-refresh-token".to_string());
+        auth.cookies.insert("idsrv".to_string(), "a-cookie-value".to_string());
+
+        let mut path = std::env::temp_dir();
+        path.push(format!("fluminurs-session-test-{:?}.json", std::thread::current().id()));
+        auth.save_session(&path).expect("Unable to save session");
+
+        let restored = Authorization::load_session(&path).expect("Unable to load session");
+        std::fs::remove_file(&path).expect("Unable to remove session file");
+
+        assert_eq!(restored.jwt, auth.jwt);
+        assert_eq!(restored.refresh_token, auth.refresh_token);
+        assert_eq!(restored.cookies, auth.cookies);
+    }
+}